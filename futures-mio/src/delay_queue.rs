@@ -0,0 +1,146 @@
+//! A queue of delayed values, expiring in deadline order.
+
+use std::time::Instant;
+
+use timer_wheel::{Clock, SystemClock, TimerWheel, Timeout, TimerError};
+
+/// An opaque handle to a value stored in a `DelayQueue`.
+///
+/// Returned by `DelayQueue::insert`; pass it back to `remove` or `reset` to
+/// operate on that specific entry.
+pub struct Key {
+    timeout: Timeout,
+}
+
+/// A queue of `(value, deadline)` pairs, built on top of `TimerWheel`, that
+/// yields its values out through `poll_expired` as their deadlines elapse.
+///
+/// Where `TimerWheel` only ever hands back one expired value per `poll` call,
+/// `DelayQueue` drains everything that's due in one pass and in deadline
+/// order, which is the shape most callers actually want for things like
+/// response caches, debouncing, or evicting idle connections.
+pub struct DelayQueue<T, C = SystemClock> {
+    wheel: TimerWheel<T, C>,
+}
+
+impl<T> DelayQueue<T> {
+    /// Creates a new, empty delay queue with the default `TimerWheel`
+    /// configuration.
+    pub fn new() -> DelayQueue<T> {
+        DelayQueue { wheel: TimerWheel::new() }
+    }
+}
+
+impl<T, C: Clock> DelayQueue<T, C> {
+    /// Builds a delay queue directly from an already-configured `TimerWheel`,
+    /// e.g. one built through `timer_wheel::Builder` with a custom clock,
+    /// tick duration, or capacity.
+    pub fn from_wheel(wheel: TimerWheel<T, C>) -> DelayQueue<T, C> {
+        DelayQueue { wheel: wheel }
+    }
+
+    /// Inserts `value`, to be yielded by `poll_expired` once `deadline`
+    /// elapses.
+    ///
+    /// # Errors
+    ///
+    /// See `TimerWheel::insert`.
+    pub fn insert(&mut self, value: T, deadline: Instant) -> Result<Key, TimerError> {
+        self.wheel.insert(deadline, value).map(|timeout| Key { timeout: timeout })
+    }
+
+    /// Removes `key`, returning its value if it hasn't expired yet.
+    pub fn remove(&mut self, key: &Key) -> Option<T> {
+        self.wheel.cancel(&key.timeout)
+    }
+
+    /// Resets `key`'s deadline to `new_deadline`.
+    ///
+    /// This is cheap -- see `TimerWheel::reschedule` -- whenever
+    /// `new_deadline` is still covered by the entry's current slot. Returns
+    /// `false` (without modifying `key`) if it has already expired or been
+    /// removed.
+    ///
+    /// # Errors
+    ///
+    /// See `TimerWheel::reschedule`.
+    pub fn reset(&mut self, key: &mut Key, new_deadline: Instant) -> Result<bool, TimerError> {
+        match try!(self.wheel.reschedule(&key.timeout, new_deadline)) {
+            Some(timeout) => {
+                key.timeout = timeout;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Drains and returns, in deadline order, every value whose deadline has
+    /// elapsed as of `now`.
+    ///
+    /// Deadlines are compared at the wheel's tick resolution, rounded to the
+    /// nearest tick, so a value can come out up to half a tick before its
+    /// deadline; values due in the same tick are not ordered by deadline
+    /// among themselves.
+    pub fn poll_expired(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        while let Some(value) = self.wheel.poll(now) {
+            expired.push(value);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use timer_wheel::{Builder, Clock, MockClock};
+
+    use super::DelayQueue;
+
+    fn queue() -> (DelayQueue<u32, MockClock>, MockClock) {
+        let clock = MockClock::new();
+        let wheel = Builder::new().clock(clock.clone()).build();
+        (DelayQueue::from_wheel(wheel), clock)
+    }
+
+    #[test]
+    fn yields_values_in_deadline_order() {
+        let (mut queue, clock) = queue();
+        let now = clock.now();
+
+        queue.insert(1, now + Duration::from_millis(300)).unwrap();
+        queue.insert(2, now + Duration::from_millis(100)).unwrap();
+        queue.insert(3, now + Duration::from_millis(200)).unwrap();
+
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(queue.poll_expired(clock.now()), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn remove_reclaims_a_value_before_it_expires() {
+        let (mut queue, clock) = queue();
+        let now = clock.now();
+
+        let key = queue.insert(1, now + Duration::from_millis(100)).unwrap();
+        assert_eq!(queue.remove(&key), Some(1));
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(queue.poll_expired(clock.now()), Vec::new());
+    }
+
+    #[test]
+    fn reset_pushes_a_deadline_back() {
+        let (mut queue, clock) = queue();
+        let now = clock.now();
+
+        let mut key = queue.insert(1, now + Duration::from_millis(100)).unwrap();
+        assert!(queue.reset(&mut key, now + Duration::from_millis(10_000)).unwrap());
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(queue.poll_expired(clock.now()), Vec::new());
+
+        clock.advance(Duration::from_millis(10_000));
+        assert_eq!(queue.poll_expired(clock.now()), vec![1]);
+    }
+}
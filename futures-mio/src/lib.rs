@@ -0,0 +1,12 @@
+//! Timer facilities used by the mio-based event loop.
+
+#[macro_use]
+extern crate log;
+extern crate slab;
+
+mod timer_wheel;
+mod delay_queue;
+
+pub use timer_wheel::{Builder, Clock, IntervalHandle, MockClock, SystemClock, TimerError,
+                       Timeout, TimerWheel};
+pub use delay_queue::{DelayQueue, Key};
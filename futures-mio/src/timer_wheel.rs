@@ -1,11 +1,63 @@
 //! A timer wheel implementation
 
+use std::cell::Cell;
 use std::cmp;
 use std::mem;
+use std::rc::Rc;
 use std::time::{Instant, Duration};
 
 use slab::Slab;
 
+/// A source of the current time.
+///
+/// `TimerWheel` is generic over this so that it can be driven by something
+/// other than the real clock, e.g. a `MockClock` in tests that need to assert
+/// exact firing order without actually sleeping.
+pub trait Clock {
+    /// Returns the current instant, as far as this clock is concerned.
+    fn now(&self) -> Instant;
+}
+
+/// The default `Clock`, backed by `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A `Clock` whose notion of "now" is advanced programmatically rather than
+/// tracking the real clock.
+///
+/// Cloning a `MockClock` produces another handle to the same underlying time,
+/// so a clock handed off to a `TimerWheel` via `Builder::clock` can still be
+/// advanced from outside afterwards.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl MockClock {
+    /// Creates a new mock clock, initialized to the current real time.
+    pub fn new() -> MockClock {
+        MockClock { now: Rc::new(Cell::new(Instant::now())) }
+    }
+
+    /// Advances this clock forward by `dur`.
+    pub fn advance(&self, dur: Duration) {
+        let now = self.now.get();
+        self.now.set(now + dur);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
 /// An implementation of a timer wheel where data can be associated with each
 /// timer firing.
 ///
@@ -19,18 +71,24 @@ use slab::Slab;
 /// each time. The time delta between each slot of a time wheel is of a fixed
 /// length, meaning that if a timeout is scheduled between two slots it'll end
 /// up getting scheduled into the later slot.
-pub struct TimerWheel<T> {
-    // Actual timer wheel itself.
-    //
-    // Each slot represents a fixed duration of time, and this wheel also
-    // behaves like a ring buffer. All timeouts scheduled will correspond to one
-    // slot and therefore each slot has a linked list of timeouts scheduled in
-    // it. Right now linked lists are done through indices into the `slab`
-    // below.
-    //
-    // Each slot also contains the next timeout associated with it (the minimum
-    // of the entire linked list).
-    wheel: Vec<Slot>,
+///
+/// Internally this is a *hierarchical* timer wheel, modeled after the time
+/// driver in tokio. Rather than a single ring of slots (which means a timeout
+/// far in the future aliases into a near-term slot and has to get shuffled
+/// around on every lap), timeouts are kept in one of several levels. Level 0
+/// covers the next `SLOTS_PER_LEVEL` ticks at full resolution, level 1 covers
+/// `SLOTS_PER_LEVEL` times that range at `SLOTS_PER_LEVEL`x coarser
+/// resolution, and so on. As time advances and a level's slots empty out, the
+/// entries parked in the next level up get "cascaded" down into finer-grained
+/// slots. This keeps insertion, removal, and the amount of work done per tick
+/// all O(1), regardless of how far out a timeout is scheduled.
+///
+/// The wheel is generic over its `Clock`, defaulting to `SystemClock`. Swap in
+/// a `MockClock` (see `Builder::clock`) to drive it deterministically.
+pub struct TimerWheel<T, C = SystemClock> {
+    // The levels of the hierarchical wheel, ordered from finest resolution
+    // (index 0) to coarsest (index `LEVELS - 1`).
+    levels: Vec<Level>,
 
     // A slab containing all the timeout entries themselves. This is the memory
     // backing the "linked lists" in the wheel above. Each entry has a prev/next
@@ -38,19 +96,168 @@ pub struct TimerWheel<T> {
     // timeout and the time the timeout will fire.
     slab: Slab<Entry<T>, usize>,
 
+    // The source of the current time, consulted by `insert`/`poll`/
+    // `next_timeout` instead of calling `Instant::now()` directly.
+    clock: C,
+
     // The instant that this timer was created, through which all other timeout
     // computations are relative to.
     start: Instant,
 
-    // State used during `poll`. The `cur_wheel_tick` field is the current tick
-    // we've poll'd to. That is, all events from `cur_wheel_tick` to the
-    // actual current tick in time still need to be processed.
-    //
-    // The `cur_slab_idx` variable is basically just an iterator over the linked
-    // list associated with a wheel slot. This will get incremented as we move
-    // forward in `poll`
-    cur_wheel_tick: u64,
-    cur_slab_idx: usize,
+    // The current tick we've poll'd to. That is, all events from `cur_tick` to
+    // the actual current tick in time still need to be processed.
+    cur_tick: u64,
+
+    // The configured resolution of a tick, in milliseconds.
+    tick_ms: u64,
+
+    // The number of slots in each level (a power of two, at most 64 so it
+    // still fits the `occupied` bitmap), and `num_slots - 1` precomputed as a
+    // mask.
+    num_slots: usize,
+    slot_mask: u64,
+
+    // `log2(num_slots)`, i.e. how many bits of a tick each level accounts for.
+    slot_bits: u32,
+
+    // The maximum number of in-flight timeouts the slab is allowed to grow
+    // to, as configured via `Builder::capacity`. `None` (the default) means
+    // the slab is unbounded and doubles in size as needed, matching the
+    // original, pre-`Builder` behavior.
+    capacity: Option<usize>,
+
+    // A counter handed out (and incremented) as each entry's `generation`,
+    // so a `Timeout`/`IntervalHandle` can be matched back to "the entry it
+    // was issued for" even after a periodic re-arm changes that entry's
+    // `when` while keeping its slab index.
+    next_generation: u64,
+}
+
+/// A builder for configuring and constructing a `TimerWheel`.
+///
+/// A `TimerWheel` defaults to a 100ms tick, 64 slots per level, and a slab
+/// that starts pre-sized for 256 in-flight timeouts but grows (doubling)
+/// as needed, with no upper bound. Use `Builder` to change any of these, for
+/// example to get finer resolution at the cost of a shorter per-level
+/// horizon, or to impose a hard cap on concurrent timeouts.
+pub struct Builder<C = SystemClock> {
+    tick: Duration,
+    num_slots: usize,
+    capacity: Option<usize>,
+    clock: C,
+}
+
+impl Builder<SystemClock> {
+    /// Creates a new builder with the default configuration, driven by the
+    /// real (`SystemClock`) clock.
+    pub fn new() -> Builder<SystemClock> {
+        Builder {
+            tick: Duration::from_millis(TICK_MS),
+            num_slots: SLOTS_PER_LEVEL,
+            capacity: None,
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<C: Clock> Builder<C> {
+    /// Sets the approximate duration of one tick of the wheel.
+    ///
+    /// This is the finest resolution at which timeouts fire; two timeouts
+    /// scheduled within the same tick of each other will fire together.
+    pub fn tick_duration(mut self, tick: Duration) -> Builder<C> {
+        self.tick = tick;
+        self
+    }
+
+    /// Sets the number of slots in each level of the wheel.
+    ///
+    /// This is rounded up to the next power of two so the bitmask trick used
+    /// to map ticks to slots still works. It must round up to at least 2, so
+    /// each level accounts for at least one bit of a tick, and to no more
+    /// than 64, since slot occupancy is tracked in a `u64` bitmap.
+    pub fn num_slots(mut self, num_slots: usize) -> Builder<C> {
+        self.num_slots = num_slots;
+        self
+    }
+
+    /// Sets the maximum number of in-flight timeouts the wheel will hold at
+    /// once, used both to pre-size the internal slab and as a hard cap:
+    /// once reached, further `insert` calls return `TimerError::NoCapacity`
+    /// rather than growing the slab further.
+    ///
+    /// Without this call the slab has no cap and instead doubles in size
+    /// whenever it runs out of room.
+    pub fn capacity(mut self, capacity: usize) -> Builder<C> {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Swaps in `clock` as the source of the current time, e.g. a `MockClock`
+    /// to drive the resulting wheel deterministically in tests.
+    pub fn clock<C2: Clock>(self, clock: C2) -> Builder<C2> {
+        Builder {
+            tick: self.tick,
+            num_slots: self.num_slots,
+            capacity: self.capacity,
+            clock: clock,
+        }
+    }
+
+    /// Constructs the `TimerWheel` with the configuration built up so far.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_slots`, rounded up to a power of two, is less than 2 or
+    /// greater than 64.
+    pub fn build<T>(self) -> TimerWheel<T, C> {
+        let num_slots = self.num_slots.next_power_of_two();
+        assert!(num_slots >= 2 && num_slots <= 64,
+                "num_slots must round up to between 2 and 64");
+        let slot_bits = num_slots.trailing_zeros();
+
+        let millis = self.tick.as_secs()
+            .checked_mul(1_000)
+            .and_then(|ms| ms.checked_add(self.tick.subsec_nanos() as u64 / 1_000_000))
+            .expect("tick duration too large");
+        let tick_ms = cmp::max(1, millis);
+
+        let levels = (0..LEVELS).map(|_| {
+            Level {
+                slots: vec![Slot { head: EMPTY, next_timeout: None }; num_slots],
+                occupied: 0,
+            }
+        }).collect();
+
+        let start = self.clock.now();
+
+        TimerWheel {
+            levels: levels,
+            slab: Slab::new_starting_at(1, self.capacity.unwrap_or(256)),
+            clock: self.clock,
+            start: start,
+            cur_tick: 0,
+            tick_ms: tick_ms,
+            num_slots: num_slots,
+            slot_mask: (num_slots - 1) as u64,
+            slot_bits: slot_bits,
+            capacity: self.capacity,
+            next_generation: 0,
+        }
+    }
+}
+
+// One level of the hierarchical wheel: `num_slots` slots (`SLOTS_PER_LEVEL`
+// unless configured otherwise through `Builder::num_slots`), each of which
+// is a linked list (through the `slab`) of entries due to fire during that
+// slot's window of ticks.
+struct Level {
+    slots: Vec<Slot>,
+
+    // A bitmap mirroring `slots`: bit `i` is set iff `slots[i]` is non-empty.
+    // This lets us jump straight to the next occupied slot with
+    // `trailing_zeros` instead of scanning every slot.
+    occupied: u64,
 }
 
 #[derive(Clone)]
@@ -61,40 +268,96 @@ struct Slot {
 
 struct Entry<T> {
     data: T,
+
+    // Identifies this entry independent of its slab index, so a `Timeout`
+    // can tell "the entry I was issued for" apart from an unrelated entry
+    // that has since reused the same slab slot. Assigned once, when the
+    // entry is first allocated; unlike `when` it's never rewritten by a
+    // periodic re-arm.
+    generation: u64,
+
+    // The latest instant this entry is allowed to fire at; this is what
+    // determines which slot it's placed in.
     when: Instant,
+
+    // The earliest instant this entry is eligible to fire at, as given to
+    // `insert_range` (`insert` and periodic entries set it equal to `when`).
+    // Since a slot is keyed off `when`/`latest`, an entry only fires once the
+    // wheel reaches `when`'s tick, so neither `poll` nor `reschedule` reads
+    // this field; `reschedule` does not update it either.
+    earliest: Instant,
+
+    // The level/slot this entry is currently linked into, so it can be
+    // unlinked in O(1) without recomputing its coordinates (which, after
+    // cascading, may no longer match where `when` would place it today).
+    level: usize,
+    slot: usize,
+
     prev: usize,
     next: usize,
+
+    // If this is a periodic entry (inserted via `insert_interval`), the
+    // period it re-arms at, plus a clone shim captured where `T: Clone` was
+    // known so `poll` can hand the caller a copy of `data` on every firing
+    // without requiring `T: Clone` itself.
+    interval: Option<Interval<T>>,
+}
+
+struct Interval<T> {
+    period: Duration,
+    reclone: Box<Fn(&T) -> T>,
 }
 
 /// A timeout which has been scheduled with a timer wheel.
 ///
 /// This can be used to later cancel a timeout, if necessary.
 pub struct Timeout {
-    when: Instant,
+    // Matched against the slab entry's own `generation` to guard against the
+    // ABA problem where `slab_idx` has since been freed and reused by an
+    // unrelated entry. This used to be the entry's `when`, but periodic
+    // timeouts rewrite `when` on every firing while keeping the same slab
+    // slot, so a dedicated counter is needed to identify "the same entry"
+    // independent of its current deadline.
+    generation: u64,
     slab_idx: usize,
 }
 
+/// A handle to a periodic timeout scheduled with `TimerWheel::insert_interval`.
+///
+/// Pass this to `TimerWheel::cancel_interval` to stop the recurrence and
+/// reclaim the most recently stored data.
+pub struct IntervalHandle {
+    timeout: Timeout,
+}
+
+/// Errors that can occur when scheduling a timeout with a `TimerWheel`.
+#[derive(Debug)]
+pub enum TimerError {
+    /// The requested deadline is too far in the future to be represented by
+    /// this wheel's tick arithmetic.
+    Overflow,
+    /// The wheel already has as many in-flight timeouts as it was configured
+    /// (via `Builder::capacity`) to hold.
+    NoCapacity,
+}
+
 const EMPTY: usize = 0;
-const LEN: usize = 256;
-const MASK: usize = LEN - 1;
+const LEVELS: usize = 6;
+const SLOTS_PER_LEVEL: usize = 64;
 const TICK_MS: u64 = 100;
 
 impl<T> TimerWheel<T> {
     /// Creates a new timer wheel configured with no timeouts and with the
     /// default parameters.
     ///
-    /// Currently this is a timer wheel of length 256 with a 100ms time
-    /// resolution.
+    /// Currently this is a hierarchical timer wheel of 6 levels, each with
+    /// 64 slots, and a 100ms time resolution at the finest level.
     pub fn new() -> TimerWheel<T> {
-        TimerWheel {
-            wheel: vec![Slot { head: EMPTY, next_timeout: None }; LEN],
-            slab: Slab::new_starting_at(1, 256),
-            start: Instant::now(),
-            cur_wheel_tick: 0,
-            cur_slab_idx: EMPTY,
-        }
+        Builder::new().build()
     }
+}
 
+impl<T, C: Clock> TimerWheel<T, C> {
     /// Creates a new timeout to get fired at a particular point in the future.
     ///
     /// The timeout will be associated with the specified `data`, and this data
@@ -103,32 +366,108 @@ impl<T> TimerWheel<T> {
     /// The returned `Timeout` can later get passesd to `cancel` to retrieve the
     /// data and ensure the timeout doesn't fire.
     ///
-    /// This method completes in O(1) time.
+    /// This method completes in O(1) time, and never allocates when the slab
+    /// still has spare capacity.
     ///
-    /// # Panics
+    /// # Errors
+    ///
+    /// Returns `TimerError::Overflow` if `at` is too far in the future to be
+    /// represented, or `TimerError::NoCapacity` if the wheel is already
+    /// holding as many in-flight timeouts as it was configured to hold. A
+    /// deadline before the time this wheel was created is not an error; like
+    /// any deadline at or before the wheel's current tick, it is scheduled for
+    /// the next tick.
+    pub fn insert(&mut self, at: Instant, data: T) -> Result<Timeout, TimerError> {
+        self.insert_range(at, at, data)
+    }
+
+    /// Creates a new timeout that is eligible to fire any time in
+    /// `[earliest, latest]`, rather than at one precise instant.
     ///
-    /// This method will panic if `at` is before the time that this timer wheel
-    /// was created.
-    pub fn insert(&mut self, at: Instant, data: T) -> Timeout {
+    /// The entry is placed according to `latest`, exactly as `insert` would
+    /// place a timeout scheduled for `latest`. The payoff of tracking a range
+    /// instead of a point is `reschedule`: bumping `latest` forward is a
+    /// no-op as long as the new deadline is still covered by the slot the
+    /// entry already lives in, which makes rescheduling free for the common
+    /// case of a timeout (e.g. a connection keep-alive) that gets bumped
+    /// forward constantly but rarely actually fires.
+    ///
+    /// # Errors
+    ///
+    /// See `insert`. `earliest` is not validated against the wheel's
+    /// resolution; only `latest` determines placement.
+    pub fn insert_range(&mut self, earliest: Instant, latest: Instant, data: T)
+                         -> Result<Timeout, TimerError> {
         // First up, figure out where we're gonna go in the wheel. Note that if
-        // we're being scheduled on or before the current wheel tick we just
-        // make sure to defer ourselves to the next tick.
-        let mut tick = self.time_to_ticks(at);
-        if tick <= self.cur_wheel_tick {
-            debug!("moving {} to {}", tick, self.cur_wheel_tick + 1);
-            tick = self.cur_wheel_tick + 1;
+        // we're being scheduled on or before the current tick we just make
+        // sure to defer ourselves to the next tick.
+        let mut tick = try!(self.time_to_ticks(latest));
+        if tick <= self.cur_tick {
+            debug!("moving {} to {}", tick, self.cur_tick + 1);
+            tick = self.cur_tick + 1;
+        }
+
+        self.link(tick, earliest, latest, data, None)
+    }
+
+    /// Creates a timeout that, instead of firing once, fires repeatedly every
+    /// `period` starting at `first`, re-arming itself on every `poll` that
+    /// returns it.
+    ///
+    /// If a `poll` call falls behind by more than one period (e.g. the event
+    /// loop was busy), the missed firings are *not* delivered back-to-back;
+    /// the entry is skipped forward to the next period that's still in the
+    /// future, so callers see at most one firing per `poll`.
+    ///
+    /// The returned `IntervalHandle` can be passed to `cancel_interval` to
+    /// stop the recurrence and reclaim the most recently stored `data`.
+    ///
+    /// # Errors
+    ///
+    /// See `insert`.
+    pub fn insert_interval(&mut self, first: Instant, period: Duration, data: T)
+                            -> Result<IntervalHandle, TimerError>
+        where T: Clone
+    {
+        let mut tick = try!(self.time_to_ticks(first));
+        if tick <= self.cur_tick {
+            tick = self.cur_tick + 1;
         }
-        let wheel_idx = self.ticks_to_wheel_idx(tick);
-        trace!("inserting timeout at {} for {}", wheel_idx, tick);
+
+        let interval = Interval {
+            period: period,
+            reclone: Box::new(|data: &T| data.clone()),
+        };
+
+        self.link(tick, first, first, data, Some(interval))
+            .map(|timeout| IntervalHandle { timeout: timeout })
+    }
+
+    /// Links a fresh slab entry for `data`, eligible to fire any time from
+    /// `earliest` and due by `latest`, into whichever level/slot `tick`
+    /// (derived from `latest`) belongs in given the current tick.
+    fn link(&mut self, tick: u64, earliest: Instant, latest: Instant, data: T,
+            interval: Option<Interval<T>>) -> Result<Timeout, TimerError> {
+        let level = self.level_for(tick);
+        let slot_idx = self.slot_index(level, tick);
+        trace!("inserting timeout at level {} slot {} for {}", level, slot_idx, tick);
 
         // Next, make sure there's enough space in the slab for the timeout.
+        // With a configured `capacity` the slab is pre-sized to exactly that
+        // many entries, so running out of room here means we're already at
+        // the cap. Without one, the slab just doubles in size.
         if self.slab.vacant_entry().is_none() {
-            let amt = self.slab.count();
-            self.slab.grow(amt);
+            match self.capacity {
+                Some(_) => return Err(TimerError::NoCapacity),
+                None => self.slab.grow(cmp::max(1, self.slab.count())),
+            }
         }
 
         // Insert ourselves at the head of the linked list in the wheel.
-        let slot = &mut self.wheel[wheel_idx];
+        let generation = self.next_generation;
+        self.next_generation += 1;
+
+        let slot = &mut self.levels[level].slots[slot_idx];
         let prev_head;
         {
             let entry = self.slab.vacant_entry().unwrap();
@@ -136,9 +475,14 @@ impl<T> TimerWheel<T> {
 
             entry.insert(Entry {
                 data: data,
-                when: at,
+                generation: generation,
+                when: latest,
+                earliest: earliest,
+                level: level,
+                slot: slot_idx,
                 prev: EMPTY,
                 next: prev_head,
+                interval: interval,
             });
         }
         if prev_head != EMPTY {
@@ -146,17 +490,17 @@ impl<T> TimerWheel<T> {
         }
 
         // Update the wheel slot's next timeout field.
-        if at <= slot.next_timeout.unwrap_or(at) {
-            let tick = tick as u32;
-            let actual_tick = self.start + Duration::from_millis(TICK_MS) * tick;
-            let at = cmp::max(actual_tick, at);
-            slot.next_timeout = Some(at);
+        if latest <= slot.next_timeout.unwrap_or(latest) {
+            slot.next_timeout = Some(latest);
         }
 
-        Timeout {
-            when: at,
-            slab_idx: slot.head,
-        }
+        let slab_idx = slot.head;
+        self.levels[level].occupied |= 1 << slot_idx;
+
+        Ok(Timeout {
+            generation: generation,
+            slab_idx: slab_idx,
+        })
     }
 
     /// Queries this timer to see if any timeouts are ready to fire.
@@ -168,76 +512,148 @@ impl<T> TimerWheel<T> {
     ///
     /// # Panics
     ///
-    /// This method will panic if `at` is before the instant that this timer
-    /// wheel was created.
+    /// This method will panic if `at` is too far in the future to be
+    /// represented; see `TimerError::Overflow` on `insert`. A deadline before
+    /// the instant this wheel was created is not an error.
     pub fn poll(&mut self, at: Instant) -> Option<T> {
-        let wheel_tick = self.time_to_ticks(at);
-
-        trace!("polling {} => {}", self.cur_wheel_tick, wheel_tick);
-
-        // Advance forward in time to the `wheel_tick` specified.
-        //
-        // TODO: don't visit slots in the wheel more than once
-        while self.cur_wheel_tick <= wheel_tick {
-            let head = self.cur_slab_idx;
-            trace!("next head[{} => {}]: {}",
-                   self.cur_wheel_tick, wheel_tick, head);
-
-            // If the current slot has no entries or we're done iterating go to
-            // the next tick.
-            if head == EMPTY {
-                self.cur_wheel_tick += 1;
-                let idx = self.ticks_to_wheel_idx(self.cur_wheel_tick);
-                self.cur_slab_idx = self.wheel[idx].head;
-                continue
-            }
+        let target_tick = self.time_to_ticks(at)
+            .expect("deadline too far in the future to poll");
 
-            // If we're starting to iterate over a slot, clear its timeout as
-            // we're probably going to remove entries. As we skip over each
-            // element of this slot we'll restore the `next_timeout` field if
-            // necessary.
-            let idx = self.ticks_to_wheel_idx(self.cur_wheel_tick);
-            if head == self.wheel[idx].head {
-                self.wheel[idx].next_timeout = None;
-            }
+        trace!("polling {} => {}", self.cur_tick, target_tick);
+
+        while self.cur_tick <= target_tick {
+            let slot_idx = self.slot_index(0, self.cur_tick);
+            let head = self.levels[0].slots[slot_idx].head;
 
-            // Otherwise, continue iterating over the linked list in the wheel
-            // slot we're on and remove anything which has expired.
-            self.cur_slab_idx = self.slab[head].next;
-            let head_timeout = self.slab[head].when;
-            if self.time_to_ticks(head_timeout) <= self.time_to_ticks(at) {
+            if head != EMPTY {
+                if self.slab[head].interval.is_some() {
+                    return Some(self.fire_periodic(head, at, target_tick))
+                }
                 return self.remove_slab(head).map(|e| e.data)
+            }
+
+            // This slot is empty; rather than incrementing tick-by-tick,
+            // rotate the occupancy bitmap so the current slot is the low bit
+            // and use `trailing_zeros` to jump straight to the next occupied
+            // slot in this lap. If none remain in the lap (or we'd run past
+            // `target_tick`), jump only as far as the lap boundary or
+            // `target_tick`, whichever comes first.
+            let remaining_in_lap = (self.num_slots - slot_idx) as u64;
+            let occ = self.levels[0].occupied;
+            let skip = if occ == 0 {
+                remaining_in_lap
             } else {
-                let next = self.wheel[idx].next_timeout.unwrap_or(head_timeout);
-                if head_timeout <= next {
-                    self.wheel[idx].next_timeout = Some(head_timeout);
-                }
+                cmp::min(occ.rotate_right(slot_idx as u32).trailing_zeros() as u64,
+                          remaining_in_lap)
+            };
+            let skip = cmp::min(skip, target_tick - self.cur_tick + 1);
+
+            self.cur_tick += skip;
+
+            // Every time the finest level wraps back around to slot 0 we've
+            // completed a full lap of it, so it's time to cascade entries
+            // down from the next level up into fresh, finer-grained slots.
+            if skip > 0 && self.slot_index(0, self.cur_tick) == 0 {
+                self.cascade(1);
             }
         }
 
         None
     }
 
+    // Drains the slot that `cur_tick` now points to at `level` and reinserts
+    // each of its entries relative to the current tick, which may place them
+    // into a finer level (possibly level 0, if they're now imminent). If this
+    // is slot 0, i.e. `level` has just wrapped around, the next level up is
+    // cascaded too.
+    fn cascade(&mut self, level: usize) {
+        if level >= LEVELS {
+            return
+        }
+
+        let slot_idx = self.slot_index(level, self.cur_tick);
+        let head = mem::replace(&mut self.levels[level].slots[slot_idx].head, EMPTY);
+        self.levels[level].slots[slot_idx].next_timeout = None;
+        self.levels[level].occupied &= !(1 << slot_idx);
+
+        let mut idx = head;
+        while idx != EMPTY {
+            let next = self.slab[idx].next;
+            let when = self.slab[idx].when;
+            // `when` was already validated by `time_to_ticks` back when this
+            // entry was first inserted, so it can't have started overflowing
+            // since.
+            let tick = self.time_to_ticks(when)
+                .expect("previously valid deadline became unrepresentable");
+            self.relink(idx, tick);
+            idx = next;
+        }
+
+        if slot_idx == 0 {
+            self.cascade(level + 1);
+        }
+    }
+
+    // Reinserts an already-slab-allocated entry at `idx` into the slot that
+    // `tick` maps to today, without touching the slab entry's `data`.
+    fn relink(&mut self, idx: usize, tick: u64) {
+        let level = self.level_for(tick);
+        let slot_idx = self.slot_index(level, tick);
+
+        let slot = &mut self.levels[level].slots[slot_idx];
+        let prev_head = mem::replace(&mut slot.head, idx);
+        {
+            let entry = &mut self.slab[idx];
+            entry.prev = EMPTY;
+            entry.next = prev_head;
+            entry.level = level;
+            entry.slot = slot_idx;
+        }
+        if prev_head != EMPTY {
+            self.slab[prev_head].prev = idx;
+        }
+
+        let when = self.slab[idx].when;
+        if when <= slot.next_timeout.unwrap_or(when) {
+            slot.next_timeout = Some(when);
+        }
+        self.levels[level].occupied |= 1 << slot_idx;
+    }
+
     /// Returns the instant in time that corresponds to the next timeout
     /// scheduled in this wheel.
     pub fn next_timeout(&self) -> Option<Instant> {
-        // TODO: can this be optimized to not look at the whole array?
-        let timeouts = self.wheel.iter().map(|slot| slot.next_timeout);
-        let min = timeouts.fold(None, |prev, cur| {
-            match (prev, cur) {
-                (None, cur) => cur,
-                (Some(time), None) => Some(time),
-                (Some(a), Some(b)) => Some(cmp::min(a, b)),
-            }
-        });
-        let time = min.map(|min| min + Duration::from_millis(TICK_MS / 2));
+        let min = (0..LEVELS)
+            .filter_map(|level| self.next_timeout_at_level(level))
+            .fold(None, |prev, cur| {
+                match prev {
+                    None => Some(cur),
+                    Some(prev) => Some(cmp::min(prev, cur)),
+                }
+            });
+        let time = min.map(|min| min + Duration::from_millis(self.tick_ms / 2));
         if let Some(time) = time {
             debug!("next timeout {:?}", time);
-            debug!("now          {:?}", Instant::now());
+            debug!("now          {:?}", self.clock.now());
         }
         return time
     }
 
+    // Finds the soonest `next_timeout` among the slots of `level`, in O(1),
+    // by rotating the level's occupancy bitmap so the current tick's slot is
+    // the low bit and using `trailing_zeros` to land directly on the nearest
+    // occupied slot at or after it.
+    fn next_timeout_at_level(&self, level: usize) -> Option<Instant> {
+        let occ = self.levels[level].occupied;
+        if occ == 0 {
+            return None
+        }
+        let cur_slot = self.slot_index(level, self.cur_tick);
+        let offset = occ.rotate_right(cur_slot as u32).trailing_zeros() as usize;
+        let slot_idx = (cur_slot + offset) % self.num_slots;
+        self.levels[level].slots[slot_idx].next_timeout
+    }
+
     /// Cancels the specified timeout.
     ///
     /// For timeouts previously registered via `insert` they can be passed back
@@ -251,13 +667,149 @@ impl<T> TimerWheel<T> {
     /// This method may panic if `timeout` wasn't created by this timer wheel.
     pub fn cancel(&mut self, timeout: &Timeout) -> Option<T> {
         match self.slab.get(timeout.slab_idx) {
-            Some(e) if e.when == timeout.when => {}
+            Some(e) if e.generation == timeout.generation => {}
             _ => return None,
         }
 
         self.remove_slab(timeout.slab_idx).map(|e| e.data)
     }
 
+    /// Cancels a periodic timeout previously scheduled with
+    /// `insert_interval`, stopping the recurrence and reclaiming the most
+    /// recently stored data.
+    ///
+    /// This method completes in O(1) time.
+    pub fn cancel_interval(&mut self, handle: &IntervalHandle) -> Option<T> {
+        self.cancel(&handle.timeout)
+    }
+
+    /// Reschedules an existing timeout to (approximately) fire by `new_latest`
+    /// instead of its current deadline.
+    ///
+    /// If `new_latest` still falls within the span covered by the slot the
+    /// timeout already occupies, this just updates the stored deadline in
+    /// place -- no slab remove/reinsert, no relinking. This makes bumping a
+    /// timeout forward free in the common case (e.g. resetting a connection's
+    /// idle timeout on every read). The timeout still fires at `new_latest`'s
+    /// tick, since entries in a coarser level are re-placed by their stored
+    /// deadline when they cascade down.
+    ///
+    /// Returns `Ok(None)` if `timeout` has already fired or been cancelled.
+    pub fn reschedule(&mut self, timeout: &Timeout, new_latest: Instant)
+                       -> Result<Option<Timeout>, TimerError> {
+        let slab_idx = timeout.slab_idx;
+        let (level, slot_idx) = match self.slab.get(slab_idx) {
+            Some(e) if e.generation == timeout.generation => (e.level, e.slot),
+            _ => return Ok(None),
+        };
+
+        let mut new_tick = try!(self.time_to_ticks(new_latest));
+        if new_tick <= self.cur_tick {
+            new_tick = self.cur_tick + 1;
+        }
+        let new_level = self.level_for(new_tick);
+        let new_slot_idx = self.slot_index(new_level, new_tick);
+
+        self.slab[slab_idx].when = new_latest;
+
+        if new_level == level && new_slot_idx == slot_idx {
+            trace!("reschedule {} is a no-op, still in level {} slot {}", slab_idx, level, slot_idx);
+            let slot = &mut self.levels[level].slots[slot_idx];
+            if new_latest <= slot.next_timeout.unwrap_or(new_latest) {
+                slot.next_timeout = Some(new_latest);
+            }
+        } else {
+            trace!("reschedule {} moves to level {} slot {}", slab_idx, new_level, new_slot_idx);
+            self.unlink(slab_idx);
+            self.relink(slab_idx, new_tick);
+        }
+
+        Ok(Some(Timeout { generation: timeout.generation, slab_idx: slab_idx }))
+    }
+
+    // Detaches slab entry `idx` from whichever slot it's currently linked
+    // into, without removing it from the slab itself (unlike `remove_slab`).
+    fn unlink(&mut self, idx: usize) {
+        let (level, slot, prev, next) = {
+            let e = &self.slab[idx];
+            (e.level, e.slot, e.prev, e.next)
+        };
+
+        if prev == EMPTY {
+            self.levels[level].slots[slot].head = next;
+            if next == EMPTY {
+                self.levels[level].slots[slot].next_timeout = None;
+                self.levels[level].occupied &= !(1 << slot);
+            }
+        } else {
+            self.slab[prev].next = next;
+        }
+        if next != EMPTY {
+            self.slab[next].prev = prev;
+        }
+    }
+
+    // Fires the periodic entry at `idx`, returning a clone of its data and
+    // re-arming it at the next period strictly after `at`, without landing
+    // at or before `target_tick` -- the tick this `poll` call is advancing
+    // to. Clamping against `target_tick` rather than just `cur_tick` matters
+    // because a caller drains a `poll` loop with the same `at` until it
+    // returns `None` (e.g. `DelayQueue::poll_expired`); clamping only past
+    // `cur_tick` could re-arm the entry somewhere the rest of that same
+    // drain would still reach, firing it twice for one `at`.
+    //
+    // If the next period would overflow what the wheel can represent, the
+    // entry is dropped instead of re-armed, same as an out-of-range `insert`
+    // would fail, just without a caller left holding an `IntervalHandle` to
+    // see the error on.
+    fn fire_periodic(&mut self, idx: usize, at: Instant, target_tick: u64) -> T {
+        let data = {
+            let entry = &self.slab[idx];
+            (entry.interval.as_ref().unwrap().reclone)(&entry.data)
+        };
+
+        self.unlink(idx);
+
+        let period = self.slab[idx].interval.as_ref().unwrap().period;
+        let when = self.slab[idx].when;
+
+        // Skip directly to the first period strictly after `at`, rather
+        // than stepping one period at a time -- a fast-firing period polled
+        // long after its last firing would otherwise take
+        // O((at - when) / period) iterations here.
+        let when = if when <= at {
+            let period_ms = cmp::max(1, Self::duration_to_ms(period));
+            let elapsed_ms = Self::duration_to_ms(at - when);
+            let periods = elapsed_ms / period_ms + 1;
+            when + Duration::from_millis(periods * period_ms)
+        } else {
+            when + period
+        };
+
+        match self.time_to_ticks(when) {
+            Ok(mut tick) => {
+                if tick <= target_tick {
+                    tick = target_tick + 1;
+                }
+                self.slab[idx].when = when;
+                self.slab[idx].earliest = when;
+                self.relink(idx, tick);
+            }
+            Err(_) => {
+                trace!("periodic timeout {} overflowed, dropping recurrence", idx);
+                self.slab.remove(idx);
+            }
+        }
+
+        data
+    }
+
+    // Rounds `d` down to whole milliseconds, the same resolution
+    // `time_to_ticks` works in.
+    fn duration_to_ms(d: Duration) -> u64 {
+        d.as_secs().saturating_mul(1_000).saturating_add((d.subsec_nanos() / 1_000_000) as u64)
+    }
+
     fn remove_slab(&mut self, slab_idx: usize) -> Option<Entry<T>> {
         let entry = match self.slab.remove(slab_idx) {
             Some(e) => e,
@@ -266,8 +818,11 @@ impl<T> TimerWheel<T> {
 
         // Remove the node from the linked list
         if entry.prev == EMPTY {
-            let idx = self.ticks_to_wheel_idx(self.time_to_ticks(entry.when));
-            self.wheel[idx].head = entry.next;
+            self.levels[entry.level].slots[entry.slot].head = entry.next;
+            if entry.next == EMPTY {
+                self.levels[entry.level].slots[entry.slot].next_timeout = None;
+                self.levels[entry.level].occupied &= !(1 << entry.slot);
+            }
         } else {
             self.slab[entry.prev].next = entry.next;
         }
@@ -278,17 +833,105 @@ impl<T> TimerWheel<T> {
         return Some(entry)
     }
 
-    fn time_to_ticks(&self, time: Instant) -> u64 {
-        let dur = time - self.start;
+    // Converts `time` into a tick count relative to `self.start`. A `time`
+    // before `self.start` is clamped to tick 0 rather than erroring, since
+    // the caller's intent ("fire as soon as possible") is unambiguous; only a
+    // deadline too far in the future to add up is a real error.
+    fn time_to_ticks(&self, time: Instant) -> Result<u64, TimerError> {
+        let dur = if time > self.start { time - self.start } else { Duration::new(0, 0) };
         let ms = dur.subsec_nanos() as u64 / 1_000_000;
-        let ms = dur.as_secs()
-                    .checked_mul(1_000)
-                    .and_then(|m| m.checked_add(ms))
-                    .expect("overflow scheduling timeout");
-        (ms + TICK_MS / 2) / TICK_MS
+        let ms = match dur.as_secs().checked_mul(1_000).and_then(|m| m.checked_add(ms)) {
+            Some(ms) => ms,
+            None => return Err(TimerError::Overflow),
+        };
+        Ok((ms + self.tick_ms / 2) / self.tick_ms)
+    }
+
+    // Finds the coarsest level whose resolution is fine enough to distinguish
+    // `tick` from the current tick, i.e. the highest level at which `tick` and
+    // `cur_tick` still fall into different slots. This is the level a
+    // newly-scheduled (or cascaded) entry for `tick` belongs in.
+    fn level_for(&self, tick: u64) -> usize {
+        let masked = self.cur_tick ^ tick;
+        if masked == 0 {
+            return 0
+        }
+        let significant_bit = 63 - masked.leading_zeros();
+        cmp::min((significant_bit / self.slot_bits) as usize, LEVELS - 1)
+    }
+
+    fn slot_index(&self, level: usize, tick: u64) -> usize {
+        ((tick >> (level as u32 * self.slot_bits)) & self.slot_mask) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Builder, Clock, MockClock, TimerError, TimerWheel};
+
+    fn wheel() -> (TimerWheel<u32, MockClock>, MockClock) {
+        let clock = MockClock::new();
+        let wheel = Builder::new().clock(clock.clone()).build();
+        (wheel, clock)
+    }
+
+    #[test]
+    fn fires_in_deadline_order_across_a_cascade() {
+        let (mut wheel, clock) = wheel();
+        let now = clock.now();
+
+        // Far enough out to land in a higher level and need a cascade down;
+        // scheduled to still fire after the near-term entry below.
+        wheel.insert(now + Duration::from_millis(100 * 200), 1).unwrap();
+        wheel.insert(now + Duration::from_millis(100 * 5), 2).unwrap();
+
+        clock.advance(Duration::from_millis(100 * 5));
+        assert_eq!(wheel.poll(clock.now()), Some(2));
+        assert_eq!(wheel.poll(clock.now()), None);
+
+        clock.advance(Duration::from_millis(100 * 195));
+        assert_eq!(wheel.poll(clock.now()), Some(1));
+        assert_eq!(wheel.poll(clock.now()), None);
+    }
+
+    #[test]
+    fn insert_overflow_is_reported_as_an_error() {
+        let (mut wheel, clock) = wheel();
+
+        let far_future = clock.now() + Duration::from_secs(u64::max_value() / 1_000 + 1);
+        match wheel.insert(far_future, 1) {
+            Err(TimerError::Overflow) => {}
+            Err(TimerError::NoCapacity) => panic!("expected Overflow, got NoCapacity"),
+            Ok(_) => panic!("expected Overflow, got Ok"),
+        }
     }
 
-    fn ticks_to_wheel_idx(&self, ticks: u64) -> usize {
-        (ticks as usize) & MASK
+    #[test]
+    fn periodic_timeout_fires_once_per_drain_and_reschedules() {
+        let (mut wheel, clock) = wheel();
+        let now = clock.now();
+
+        let handle = wheel.insert_interval(now + Duration::from_millis(100),
+                                            Duration::from_millis(100), 1).unwrap();
+
+        // Advance well past several periods in one jump; the catch-up should
+        // collapse to a single firing rather than one per missed period.
+        clock.advance(Duration::from_millis(100 * 10));
+        let mut fired = 0;
+        while let Some(v) = wheel.poll(clock.now()) {
+            assert_eq!(v, 1);
+            fired += 1;
+        }
+        assert_eq!(fired, 1);
+
+        assert!(wheel.poll(clock.now()).is_none());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(wheel.poll(clock.now()), Some(1));
+
+        assert_eq!(wheel.cancel_interval(&handle), Some(1));
+        clock.advance(Duration::from_millis(100 * 5));
+        assert_eq!(wheel.poll(clock.now()), None);
     }
-}
\ No newline at end of file
+}